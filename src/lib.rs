@@ -47,23 +47,50 @@
 //!   is owned. This is most significant before generic associated
 //!   types stabilise, but it will remain an advantage for the
 //!   simplicity of some use cases, compared to
-//!   [Mutex](std::sync::Mutex) or [RwLock](std::sync::RwLock).
+//!   [Mutex](std::sync::Mutex) or [RwLock](std::sync::RwLock). The one
+//!   exception is [mutate_exclusive](CloneReplace::mutate_exclusive):
+//!   its guard holds a real [MutexGuard](std::sync::MutexGuard) rather
+//!   than an owned clone, since that is the whole point of opting into
+//!   it, so it borrows from the [CloneReplace] it was created from.
 //! - Mutation is expensive. A full copy is made every time you create
 //!   a mutation guard by calling [mutate](CloneReplace::mutate) on
-//!   [CloneReplace].
+//!   [CloneReplace]. For large structures where each write only
+//!   touches a small part of the data, [OpLogReplace] replays small
+//!   operations onto two internally-held copies instead, trading the
+//!   requirement that changes be expressible as an [Absorb] operation
+//!   for avoiding the full clone.
 //! - The memory overhead can be large. For scenarios with very long
 //!   running readers, you may end up with many copies of your data
 //!   being stored simultaneously.
+//! - Every [access](CloneReplace::access) pays for an atomic load and
+//!   an [Arc](std::sync::Arc) strong-count increment. A reader that
+//!   polls in a tight loop should use [cache](CloneReplace::cache)
+//!   instead, which still does an atomic load to check for a change,
+//!   but only pays for the strong-count increment when the reference
+//!   version has actually changed.
+//! - A reader that only cares about part of `T` can narrow its view
+//!   with [map](CloneReplace::map), rather than pinning and
+//!   dereferencing the whole structure.
 //! - In the presence of multiple writers, it's entirely possible to
 //!   lose updates, because multiple writers are not prevented from
 //!   existing at the same time. Whatever state is set will always be
 //!   internally consistent, but you give up guaranteed external
-//!   consistency.
+//!   consistency. If this is a problem, [update](CloneReplace::update)
+//!   provides a compare-and-swap retry loop that never loses an
+//!   update, at the cost of potentially re-running the supplied
+//!   closure, and
+//!   [mutate_exclusive](CloneReplace::mutate_exclusive) provides a
+//!   guard that serializes writers through an internal lock instead.
 
 use arc_swap::ArcSwap;
 use core::ops::{Deref, DerefMut, Drop};
+use std::cell::Cell;
 use std::fmt::{Display, Formatter, Result};
-use std::sync::Arc;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+mod oplog;
+pub use oplog::{Absorb, OpLogReplace, Snapshot};
 
 /// A shareable store for data which provides owned references.
 ///
@@ -79,12 +106,14 @@ use std::sync::Arc;
 #[derive(Debug)]
 pub struct CloneReplace<T> {
     data: Arc<ArcSwap<T>>,
+    exclusive: Arc<Mutex<()>>,
 }
 
 impl<T> Clone for CloneReplace<T> {
     fn clone(&self) -> Self {
         Self {
             data: self.data.clone(),
+            exclusive: self.exclusive.clone(),
         }
     }
 }
@@ -111,6 +140,7 @@ impl<T> CloneReplace<T> {
     pub fn new(data: T) -> Self {
         Self {
             data: Arc::new(ArcSwap::new(Arc::new(data))),
+            exclusive: Arc::new(Mutex::new(())),
         }
     }
 
@@ -134,6 +164,67 @@ impl<T> CloneReplace<T> {
         self.data.load_full()
     }
 
+    /// Create a per-thread cache for cheap repeated reads.
+    ///
+    /// [access](CloneReplace::access) always performs an atomic load
+    /// plus an [Arc](std::sync::Arc) strong-count increment, which is
+    /// wasteful for a hot reader that polls the same value in a tight
+    /// loop. The returned [CloneReplaceCache] holds its own clone of
+    /// the current reference version, and its
+    /// [load](CloneReplaceCache::load) method only pays for a full
+    /// [access](CloneReplace::access) when the reference version has
+    /// actually changed since the last call.
+    ///
+    /// Example:
+    /// ```rust
+    /// use clone_replace::CloneReplace;
+    ///
+    /// let c = CloneReplace::new(1);
+    /// let mut cache = c.cache();
+    /// assert_eq!(**cache.load(), 1);
+    /// ```
+    pub fn cache(&self) -> CloneReplaceCache<T> {
+        CloneReplaceCache {
+            origin: self.clone(),
+            cached: self.access(),
+            _not_sync: PhantomData,
+        }
+    }
+
+    /// Project a read-only view onto part of the data.
+    ///
+    /// `access()` always hands back an
+    /// [Arc]`<T>` for the whole structure, even if a reader only
+    /// cares about one field of it. `map` takes a projection function
+    /// and returns a [Projection], whose own
+    /// [access](Projection::access) method yields a
+    /// [ProjectedAccess] that dereferences directly to `&U`, while
+    /// internally keeping the whole snapshot alive.
+    ///
+    /// Example:
+    /// ```rust
+    /// use clone_replace::CloneReplace;
+    ///
+    /// struct Foo {
+    ///     a: i32,
+    ///     b: String,
+    /// }
+    ///
+    /// let c = CloneReplace::new(Foo { a: 1, b: "hello".to_string() });
+    /// let a = c.map(|foo| &foo.a);
+    /// assert_eq!(*a.access(), 1);
+    /// ```
+    pub fn map<U, F>(&self, f: F) -> Projection<T, U, F>
+    where
+        F: Fn(&T) -> &U,
+    {
+        Projection {
+            origin: self.clone(),
+            project: Arc::new(f),
+            _marker: PhantomData,
+        }
+    }
+
     fn set_value(&self, value: T) {
         self.data.swap(Arc::new(value));
     }
@@ -172,6 +263,200 @@ impl<T: Clone> CloneReplace<T> {
             data: Some(inner.clone()),
         }
     }
+
+    /// Create a mutable replacement for the reference data, serialized
+    /// with respect to other exclusive writers.
+    ///
+    /// This is a complement to [mutate](CloneReplace::mutate) for
+    /// callers that cannot tolerate lost updates. Acquiring the guard
+    /// blocks on an internal [Mutex](std::sync::Mutex) until any other
+    /// [ExclusiveMutateGuard] for this [CloneReplace] has been
+    /// dropped, so the clone-edit-writeback sequence is atomic with
+    /// respect to other exclusive writers, and no update made through
+    /// this method is ever lost. Readers are unaffected, and still get
+    /// their cheap owned snapshots with no blocking.
+    ///
+    /// Mixing [mutate](CloneReplace::mutate) and `mutate_exclusive` is
+    /// unordered: the exclusive lock only serializes other exclusive
+    /// writers, so a plain [mutate](CloneReplace::mutate) guard alive
+    /// at the same time can still clobber the exclusive writer's
+    /// update, or vice versa.
+    ///
+    /// Example:
+    /// ```rust
+    /// use clone_replace::CloneReplace;
+    ///
+    /// let c = CloneReplace::new(1);
+    /// let mut v = c.mutate_exclusive();
+    /// *v = 2;
+    /// drop(v);
+    /// assert_eq!(*c.access(), 2);
+    /// ```
+    pub fn mutate_exclusive(&self) -> ExclusiveMutateGuard<'_, T> {
+        let lock = self.exclusive.lock().unwrap();
+        let inner = &*self.data.load_full();
+        ExclusiveMutateGuard {
+            origin: self,
+            _lock: lock,
+            data: Some(inner.clone()),
+        }
+    }
+
+    /// Apply an update to the data without losing concurrent writes.
+    ///
+    /// Unlike [mutate](CloneReplace::mutate), which will silently
+    /// discard another writer's changes if several guards are alive
+    /// at once, `update` guarantees that every call is reflected in
+    /// the reference version, even under contention. It captures the
+    /// current reference version, clones it, and runs `f` against the
+    /// clone. If no other writer has committed in the meantime, the
+    /// result is installed as the new reference version. Otherwise,
+    /// the attempt is discarded and retried against the version that
+    /// was just committed, until it succeeds.
+    ///
+    /// This only holds among writers that go through `update`. A
+    /// [MutateGuard] or [ExclusiveMutateGuard] created before the
+    /// commit and dropped after it still overwrites the reference
+    /// version with its own copy, discarding the update.
+    ///
+    /// `f` may therefore run more than once, so it should be
+    /// idempotent with respect to being replayed: it must be safe to
+    /// throw away the effects of a losing attempt and run again from
+    /// fresh data.
+    ///
+    /// Returns the new reference version, along with whatever `f`
+    /// returned on the attempt that succeeded.
+    ///
+    /// Example:
+    /// ```rust
+    /// use clone_replace::CloneReplace;
+    ///
+    /// let c = CloneReplace::new(1);
+    /// let (v, ()) = c.update(|v| *v += 1);
+    /// assert_eq!(*v, 2);
+    /// assert_eq!(*c.access(), 2);
+    /// ```
+    pub fn update<F, R>(&self, mut f: F) -> (Arc<T>, R)
+    where
+        F: FnMut(&mut T) -> R,
+    {
+        let mut current = self.data.load_full();
+        loop {
+            let mut candidate = (*current).clone();
+            let result = f(&mut candidate);
+            let new = Arc::new(candidate);
+            let previous = self.data.compare_and_swap(&current, new.clone());
+            if Arc::ptr_eq(&*previous, &current) {
+                return (new, result);
+            }
+            current = arc_swap::Guard::into_inner(previous);
+        }
+    }
+}
+
+/// A per-thread cache of the reference version of the data.
+///
+/// Created by calling [cache](CloneReplace::cache) on [CloneReplace].
+/// Holds a clone of the [CloneReplace] alongside the last
+/// [Arc](std::sync::Arc) it observed, so that repeated reads only
+/// perform a full [access](CloneReplace::access) when the reference
+/// version has actually changed. Because it carries this cached state
+/// across calls, a `CloneReplaceCache` is not [Sync], and is intended
+/// to be owned by a single reading thread.
+pub struct CloneReplaceCache<T> {
+    origin: CloneReplace<T>,
+    cached: Arc<T>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T> CloneReplaceCache<T> {
+    /// Retrieve the current reference version of the data.
+    ///
+    /// If the reference version has not changed since the last call,
+    /// this returns the cached snapshot without touching the atomic
+    /// refcount of the underlying [Arc](std::sync::Arc). Otherwise it
+    /// refreshes the cache as if by calling
+    /// [refresh](CloneReplaceCache::refresh).
+    ///
+    /// Example:
+    /// ```rust
+    /// use clone_replace::CloneReplace;
+    ///
+    /// let c = CloneReplace::new(1);
+    /// let mut cache = c.cache();
+    /// assert_eq!(**cache.load(), 1);
+    /// let mut m = c.mutate();
+    /// *m = 2;
+    /// drop(m);
+    /// assert_eq!(**cache.load(), 2);
+    /// ```
+    pub fn load(&mut self) -> &Arc<T> {
+        if !Arc::ptr_eq(&*self.origin.data.load(), &self.cached) {
+            self.refresh();
+        }
+        &self.cached
+    }
+
+    /// Unconditionally refresh the cache from the current reference version.
+    ///
+    /// This always performs a full [access](CloneReplace::access), even
+    /// if the reference version has not changed.
+    pub fn refresh(&mut self) -> &Arc<T> {
+        self.cached = self.origin.access();
+        &self.cached
+    }
+}
+
+/// A read-only view onto part of some [CloneReplace] data.
+///
+/// Created by calling [map](CloneReplace::map) on [CloneReplace]. Call
+/// [access](Projection::access) to obtain an owned
+/// [ProjectedAccess] handle onto the projected field, in the same way
+/// [access](CloneReplace::access) yields a handle onto the whole
+/// structure.
+pub struct Projection<T, U, F> {
+    origin: CloneReplace<T>,
+    project: Arc<F>,
+    _marker: PhantomData<fn(&T) -> &U>,
+}
+
+impl<T, U, F> Projection<T, U, F>
+where
+    F: Fn(&T) -> &U,
+{
+    /// Retrieve a snapshot of the projected field's current value.
+    ///
+    /// The return value is owned, and the snapshot taken will remain
+    /// unchanging until it goes out of scope, exactly as for
+    /// [access](CloneReplace::access) on the underlying [CloneReplace].
+    pub fn access(&self) -> ProjectedAccess<T, U, F> {
+        ProjectedAccess {
+            data: self.origin.access(),
+            project: self.project.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// An owned, read-only handle onto a projected field of the data.
+///
+/// Created by calling [access](Projection::access) on a [Projection].
+/// Keeps the whole snapshot [Arc]`<T>` alive internally, but
+/// dereferences directly to the projected field `U`.
+pub struct ProjectedAccess<T, U, F> {
+    data: Arc<T>,
+    project: Arc<F>,
+    _marker: PhantomData<fn(&T) -> &U>,
+}
+
+impl<T, U, F> Deref for ProjectedAccess<T, U, F>
+where
+    F: Fn(&T) -> &U,
+{
+    type Target = U;
+    fn deref(&self) -> &U {
+        (self.project)(&self.data)
+    }
 }
 
 /// A handle to a writeable version of the data.
@@ -250,10 +535,93 @@ impl<T> Drop for MutateGuard<T> {
     }
 }
 
+/// A handle to a writeable version of the data, serialized with other
+/// exclusive writers.
+///
+/// This structure is created by the
+/// [mutate_exclusive](CloneReplace::mutate_exclusive) method on
+/// [CloneReplace]. The data held by the guard can be accessed via its
+/// [Deref] and [DerefMut] implementations, exactly as for
+/// [MutateGuard].
+///
+/// When the guard is dropped, the contents will be written back to
+/// become the new reference version of the data, and the lock held
+/// since the guard was created will be released, allowing the next
+/// exclusive writer to proceed.
+pub struct ExclusiveMutateGuard<'a, T> {
+    origin: &'a CloneReplace<T>,
+    _lock: MutexGuard<'a, ()>,
+    data: Option<T>,
+}
+
+impl<T> ExclusiveMutateGuard<'_, T> {
+    /// Discard the changes made in this mutation session.
+    ///
+    /// The changed data will not be written back to its origin.  If
+    /// you do not call discard, the changes will always be committed
+    /// when the guard goes out of scope. Either way, the lock is
+    /// released.
+    ///
+    /// Example:
+    /// ```rust
+    /// use clone_replace::CloneReplace;
+    ///
+    /// let c = CloneReplace::new(1);
+    /// let mut v = c.mutate_exclusive();
+    /// *v = 2;
+    /// v.discard();
+    /// assert_eq!(*c.access(), 1);
+    /// ```
+    pub fn discard(mut self) {
+        self.data = None;
+    }
+}
+
+impl<T> Deref for ExclusiveMutateGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        // Does not panic: the Option is only None after drop()
+        // returns, or if discard() has been called, which also drops
+        // the value immediately. There's no way to get here so long
+        // as we don't call deref() from those two methods.
+        self.data.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for ExclusiveMutateGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Does not panic: the Option is only None after drop()
+        // returns, or if discard() has been called, which also drops
+        // the value immediately. There's no way to get here so long
+        // as we don't call deref_mut() from those two methods.
+        self.data.as_mut().unwrap()
+    }
+}
+
+impl<T: Display> Display for ExclusiveMutateGuard<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        // Does not panic: the Option is only None after drop()
+        // returns, or if discard() has been called, which also drops
+        // the value immediately. There's no way to get here so long
+        // as we don't call fmt() from those two methods.
+        self.data.as_ref().unwrap().fmt(f)
+    }
+}
+
+impl<T> Drop for ExclusiveMutateGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take() {
+            self.origin.set_value(data);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::CloneReplace;
+    use std::cell::Cell;
     use std::fmt::{Display, Formatter};
+    use std::sync::Arc;
 
     #[derive(Clone, Debug)]
     struct Foo {
@@ -307,6 +675,43 @@ mod tests {
         assert_eq!(v1.a, 5);
     }
 
+    #[test]
+    fn test_mutate_exclusive() {
+        let cr = CloneReplace::new(Foo { a: 0 });
+
+        {
+            let mut m = cr.mutate_exclusive();
+            m.a = 1;
+        }
+        assert_eq!(cr.access().a, 1);
+
+        // The lock must be released on drop, so a second exclusive
+        // writer can proceed.
+        {
+            let mut m = cr.mutate_exclusive();
+            m.a = 2;
+        }
+        assert_eq!(cr.access().a, 2);
+    }
+
+    #[test]
+    fn test_mutate_exclusive_discard() {
+        let cr = CloneReplace::new(Foo { a: 5 });
+
+        {
+            let mut m = cr.mutate_exclusive();
+            m.a = 1;
+            m.discard();
+        }
+        assert_eq!(cr.access().a, 5);
+
+        // The lock must also be released when discarding.
+        let mut m = cr.mutate_exclusive();
+        m.a = 9;
+        drop(m);
+        assert_eq!(cr.access().a, 9);
+    }
+
     #[test]
     fn test_display() {
         let cr = CloneReplace::new(Foo { a: 3 });
@@ -327,6 +732,77 @@ mod tests {
         assert_eq!(v1.to_string(), "3");
     }
 
+    #[test]
+    fn test_update() {
+        let cr = CloneReplace::new(Foo { a: 0 });
+
+        let (v, doubled) = cr.update(|v| {
+            v.a += 1;
+            v.a * 2
+        });
+        assert_eq!(v.a, 1);
+        assert_eq!(doubled, 2);
+        assert_eq!(cr.access().a, 1);
+    }
+
+    #[test]
+    fn test_update_no_lost_updates() {
+        let cr = CloneReplace::new(Foo { a: 0 });
+        let interloper_ran = Cell::new(false);
+
+        // Simulate a writer committing between the load and the
+        // compare-and-swap of another `update` call by committing
+        // directly from within `f`, the first time it runs. `update`
+        // must retry rather than losing either write.
+        cr.update(|v| {
+            if !interloper_ran.get() {
+                interloper_ran.set(true);
+                let mut m = cr.mutate();
+                m.a += 100;
+                drop(m);
+            }
+            v.a += 1;
+        });
+
+        assert_eq!(cr.access().a, 101);
+    }
+
+    #[test]
+    fn test_cache() {
+        let cr = CloneReplace::new(Foo { a: 0 });
+        let mut cache = cr.cache();
+
+        assert_eq!(cache.load().a, 0);
+        // Loading again without a write should return the same cached
+        // Arc, not a fresh one.
+        let first = Arc::clone(cache.load());
+        assert!(Arc::ptr_eq(&first, cache.load()));
+
+        let mut m = cr.mutate();
+        m.a = 1;
+        drop(m);
+
+        let second = cache.load();
+        assert_eq!(second.a, 1);
+        assert!(!Arc::ptr_eq(&first, second));
+    }
+
+    #[test]
+    fn test_map() {
+        let cr = CloneReplace::new(Foo { a: 7 });
+        let projection = cr.map(|foo| &foo.a);
+
+        let view = projection.access();
+        assert_eq!(*view, 7);
+
+        let mut m = cr.mutate();
+        m.a = 9;
+        drop(m);
+
+        assert_eq!(*view, 7);
+        assert_eq!(*projection.access(), 9);
+    }
+
     #[test]
     fn test_multiple_writers() {
         let cr = CloneReplace::new(Foo { a: 4 });
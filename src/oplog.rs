@@ -0,0 +1,272 @@
+//! An alternative to [CloneReplace](crate::CloneReplace) for large
+//! structures where each write only touches a small part of the data.
+//!
+//! [CloneReplace::mutate](crate::CloneReplace::mutate) pays for a full
+//! copy of `T` on every write. [OpLogReplace] instead keeps two copies
+//! of `T` alive internally, and mutates them in place by replaying a
+//! small log of operations onto each in turn, so the cost of a write
+//! is proportional to the size of the change rather than the size of
+//! the whole structure.
+
+use arc_swap::ArcSwap;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// A type that knows how to apply an operation `O` to itself in place.
+///
+/// This is the mechanism by which [OpLogReplace] avoids cloning `T` on
+/// every write: instead of handing back a mutable copy of the whole
+/// structure, the caller describes the change as an `O`, and `T`
+/// applies it directly.
+pub trait Absorb<O> {
+    /// Apply `op` to `self`.
+    fn absorb(&mut self, op: O);
+}
+
+struct Writer<T> {
+    /// The writer's private copy. Exclusively owned by the writer
+    /// between calls to [publish](OpLogReplace::publish): never
+    /// shared until it is cloned into the published slot.
+    staged: Arc<T>,
+}
+
+/// The condition variable side of the drain wait: every [Snapshot]
+/// notifies it on drop, and [OpLogReplace::publish] waits on it
+/// instead of spinning while the buffer it wants to reclaim is still
+/// reachable from an outstanding snapshot.
+#[derive(Default)]
+struct Drain {
+    lock: Mutex<()>,
+    drained: Condvar,
+}
+
+/// A shareable store that amortizes the cost of mutation for large
+/// structures, by replaying small operations onto two copies of the
+/// data instead of cloning the whole thing on every write.
+///
+/// Readers call [access](OpLogReplace::access) exactly as they would
+/// for a [CloneReplace](crate::CloneReplace), and get back an owned,
+/// unchanging [Snapshot]`<T>` snapshot. A single writer calls
+/// [append](OpLogReplace::append) to buffer operations against a
+/// staged copy, and [publish](OpLogReplace::publish) to make that copy
+/// the new reference version.
+pub struct OpLogReplace<T, O> {
+    published: ArcSwap<T>,
+    writer: Mutex<Writer<T>>,
+    pending: Mutex<Vec<O>>,
+    drain: Arc<Drain>,
+}
+
+impl<T: Clone, O> OpLogReplace<T, O> {
+    /// Create a new [OpLogReplace] from an initial value.
+    ///
+    /// Example:
+    /// ```rust
+    /// use clone_replace::{Absorb, OpLogReplace};
+    ///
+    /// #[derive(Clone)]
+    /// struct Counter(i32);
+    ///
+    /// impl Absorb<i32> for Counter {
+    ///     fn absorb(&mut self, op: i32) {
+    ///         self.0 += op;
+    ///     }
+    /// }
+    ///
+    /// let c: OpLogReplace<Counter, i32> = OpLogReplace::new(Counter(0));
+    /// assert_eq!(c.access().0, 0);
+    /// ```
+    pub fn new(data: T) -> Self {
+        let staged = Arc::new(data.clone());
+        Self {
+            published: ArcSwap::new(Arc::new(data)),
+            writer: Mutex::new(Writer { staged }),
+            pending: Mutex::new(Vec::new()),
+            drain: Arc::new(Drain::default()),
+        }
+    }
+}
+
+impl<T, O> OpLogReplace<T, O> {
+    /// Retrieve a snapshot of the current published version of the data.
+    ///
+    /// As with [CloneReplace::access](crate::CloneReplace::access),
+    /// the return value is owned, and will not change even as later
+    /// calls to [publish](OpLogReplace::publish) advance the
+    /// reference version. A snapshot taken this way may lag behind
+    /// one [publish](OpLogReplace::publish) relative to the writer's
+    /// staged copy.
+    ///
+    /// Holding a [Snapshot] across a call to
+    /// [publish](OpLogReplace::publish) on the same thread will
+    /// deadlock that call: publishing must wait for every snapshot of
+    /// the buffer it wants to reclaim to be dropped first, and nothing
+    /// will drop yours for you. Don't hold a `Snapshot` any longer
+    /// than you need it.
+    pub fn access(&self) -> Snapshot<T> {
+        Snapshot {
+            data: Some(self.published.load_full()),
+            drain: self.drain.clone(),
+        }
+    }
+}
+
+impl<T, O> OpLogReplace<T, O>
+where
+    T: Absorb<O>,
+    O: Clone,
+{
+    /// Buffer an operation and apply it to the staged copy.
+    ///
+    /// The change is not visible to readers until
+    /// [publish](OpLogReplace::publish) is called. Intended to be
+    /// called from a single writer; concurrent callers are serialized
+    /// through an internal lock, but see the type-level docs for the
+    /// single-writer assumption this is built around.
+    pub fn append(&self, op: O) {
+        let mut writer = self.writer.lock().unwrap();
+        let staged =
+            Arc::get_mut(&mut writer.staged).expect("staged buffer exclusively owned by writer");
+        staged.absorb(op.clone());
+        self.pending.lock().unwrap().push(op);
+    }
+
+    /// Publish the staged copy, making it the new reference version.
+    ///
+    /// The previously published copy becomes the new staged copy: the
+    /// buffered operations since the last publish are replayed onto
+    /// it, bringing it up to date without ever deep-cloning `T`. Since
+    /// that copy may still be reachable from snapshots taken before
+    /// this call, publishing parks and waits until every such snapshot
+    /// has been dropped, rather than spinning.
+    ///
+    /// A [Snapshot] still held by the calling thread will never be
+    /// dropped while this call waits for it, so publishing from a
+    /// thread that is itself holding a snapshot of the buffer being
+    /// reclaimed deadlocks; see the warning on
+    /// [access](OpLogReplace::access).
+    pub fn publish(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        let ops = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        let ready = writer.staged.clone();
+        let mut stale = self.published.swap(ready);
+
+        let mut guard = self.drain.lock.lock().unwrap();
+        while Arc::strong_count(&stale) > 1 {
+            guard = self.drain.drained.wait(guard).unwrap();
+        }
+        drop(guard);
+
+        let stale_mut =
+            Arc::get_mut(&mut stale).expect("no outstanding snapshots after waiting to drain");
+        for op in ops {
+            stale_mut.absorb(op);
+        }
+        writer.staged = stale;
+    }
+}
+
+/// An owned, immutable snapshot of the published version of the data.
+///
+/// Returned by [OpLogReplace::access]. Derefs to `T`. Dropping a
+/// `Snapshot` wakes up any [publish](OpLogReplace::publish) call
+/// waiting to reclaim the buffer it points at.
+pub struct Snapshot<T> {
+    data: Option<Arc<T>>,
+    drain: Arc<Drain>,
+}
+
+impl<T> Deref for Snapshot<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Does not panic: the Option is only None after drop() has run.
+        self.data.as_deref().unwrap()
+    }
+}
+
+impl<T> Drop for Snapshot<T> {
+    fn drop(&mut self) {
+        // Drop our Arc clone before notifying, so that any publish()
+        // woken by notify_all() sees the refcount it actually dropped
+        // to, rather than racing a wakeup against the decrement and
+        // going back to sleep with nobody left to wake it again.
+        drop(self.data.take());
+        drop(self.drain.lock.lock().unwrap());
+        self.drain.drained.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Absorb, OpLogReplace};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Clone, Debug)]
+    struct Counter {
+        value: i32,
+    }
+
+    impl Absorb<i32> for Counter {
+        fn absorb(&mut self, op: i32) {
+            self.value += op;
+        }
+    }
+
+    #[test]
+    fn test_basic() {
+        let oplog: OpLogReplace<Counter, i32> = OpLogReplace::new(Counter { value: 0 });
+
+        let v1 = oplog.access();
+        assert_eq!(v1.value, 0);
+        drop(v1);
+
+        oplog.append(5);
+        // Not visible until published.
+        assert_eq!(oplog.access().value, 0);
+
+        oplog.publish();
+        assert_eq!(oplog.access().value, 5);
+    }
+
+    #[test]
+    fn test_publish_waits_for_stale_readers() {
+        let oplog: Arc<OpLogReplace<Counter, i32>> =
+            Arc::new(OpLogReplace::new(Counter { value: 0 }));
+
+        oplog.append(1);
+        oplog.publish();
+
+        // v1 pins the buffer that the second publish() below will want
+        // to reuse and replay operations onto.
+        let v1 = oplog.access();
+        oplog.append(2);
+
+        let dropper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(v1);
+        });
+
+        // Blocks until the spawned thread drops its snapshot.
+        oplog.publish();
+        dropper.join().unwrap();
+
+        assert_eq!(oplog.access().value, 3);
+    }
+
+    #[test]
+    fn test_multiple_publishes_converge() {
+        let oplog: OpLogReplace<Counter, i32> = OpLogReplace::new(Counter { value: 0 });
+
+        oplog.append(1);
+        oplog.publish();
+        assert_eq!(oplog.access().value, 1);
+
+        oplog.append(2);
+        oplog.append(3);
+        oplog.publish();
+        assert_eq!(oplog.access().value, 6);
+    }
+}